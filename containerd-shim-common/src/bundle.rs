@@ -0,0 +1,164 @@
+//! Inspection of an OCI bundle before its container is created, as done by
+//! the shims' `--validate` flag.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+const WASM_MAGIC: &[u8] = b"\0asm";
+
+/// Reads and parses the `config.json` of `bundle`.
+pub fn read_spec(bundle: &Path) -> Result<Value> {
+    let config_path = bundle.join("config.json");
+    let config =
+        std::fs::read(&config_path).with_context(|| format!("failed to read {config_path:?}"))?;
+    serde_json::from_slice(&config).with_context(|| format!("failed to parse {config_path:?}"))
+}
+
+/// The working directory of the container's process, `/` when unset.
+pub fn process_cwd(spec: &Value) -> &str {
+    spec["process"]["cwd"].as_str().unwrap_or("/")
+}
+
+/// The last value of `name` in the OCI `process.env`.
+pub fn process_env(spec: &Value, name: &str) -> Option<String> {
+    spec["process"]["env"]
+        .as_array()?
+        .iter()
+        .filter_map(|var| var.as_str()?.split_once('='))
+        .rfind(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+/// The host path of `path` as seen by the container's process: relative to
+/// `process.cwd` unless absolute, and inside the bundle's rootfs.
+pub fn resolve(bundle: &Path, spec: &Value, path: &Path) -> PathBuf {
+    let rootfs = bundle.join(spec["root"]["path"].as_str().unwrap_or("rootfs"));
+    let path = Path::new(process_cwd(spec)).join(path);
+    rootfs.join(path.strip_prefix("/").unwrap_or(&path))
+}
+
+/// Checks that `path` points at a Wasm binary.
+pub fn check_module(path: &Path) -> Result<()> {
+    let module = match std::fs::read(path) {
+        Ok(module) => module,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            bail!("module {path:?} not found")
+        }
+        Err(err) => return Err(err).with_context(|| format!("failed to read module {path:?}")),
+    };
+    if module.is_empty() {
+        bail!("module {path:?} is empty");
+    }
+    if !module.starts_with(WASM_MAGIC) {
+        bail!("module {path:?} is not a Wasm binary");
+    }
+    Ok(())
+}
+
+/// Handles `--validate <bundle>`: checks the bundle with `validate`, reports
+/// the result and exits. Returns when the shim was started for anything else.
+pub fn parse_validate(validate: fn(&Path) -> Result<()>) {
+    let os_args: Vec<_> = std::env::args_os().collect();
+    if os_args.len() == 3 && os_args[1] == "--validate" {
+        let bundle = Path::new(&os_args[2]);
+        match validate(bundle) {
+            Ok(()) => {
+                println!("{}: bundle is valid", bundle.display());
+                std::process::exit(0);
+            }
+            Err(err) => {
+                eprintln!("{}: bundle is invalid: {:#}", bundle.display(), err);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// Creates the bundle `name` in the temp dir with `config` as its
+/// `config.json` and `files` written below its `rootfs`. Meant for tests.
+#[cfg(any(test, feature = "testing"))]
+pub fn fixture_bundle(name: &str, config: &str, files: &[(&str, &[u8])]) -> PathBuf {
+    let bundle = std::env::temp_dir().join(name);
+    let rootfs = bundle.join("rootfs");
+    std::fs::create_dir_all(&rootfs).expect("failed to create rootfs");
+    std::fs::write(bundle.join("config.json"), config).expect("failed to write config.json");
+    for (path, contents) in files {
+        let path = rootfs.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).expect("failed to create directory");
+        std::fs::write(path, contents).expect("failed to write file");
+    }
+    bundle
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_paths_like_the_container() {
+        let bundle = Path::new("/bundle");
+        let spec: Value =
+            serde_json::from_str(r#"{"root": {"path": "fs"}, "process": {"cwd": "/app"}}"#)
+                .unwrap();
+        assert_eq!(
+            resolve(bundle, &spec, Path::new("app.wasm")),
+            Path::new("/bundle/fs/app/app.wasm")
+        );
+        assert_eq!(
+            resolve(bundle, &spec, Path::new("/bin/app.wasm")),
+            Path::new("/bundle/fs/bin/app.wasm")
+        );
+
+        let defaults = Value::Null;
+        assert_eq!(process_cwd(&defaults), "/");
+        assert_eq!(
+            resolve(bundle, &defaults, Path::new("app.wasm")),
+            Path::new("/bundle/rootfs/app.wasm")
+        );
+    }
+
+    #[test]
+    fn reads_fixture_bundle() {
+        let bundle = fixture_bundle(
+            "shim-common-fixture",
+            r#"{"process": {"cwd": "/app"}}"#,
+            &[("app/app.wasm", b"\0asm\x01\0\0\0")],
+        );
+        let spec = read_spec(&bundle);
+        let module = spec
+            .as_ref()
+            .map(|spec| check_module(&resolve(&bundle, spec, Path::new("app.wasm"))));
+        std::fs::remove_dir_all(&bundle).unwrap();
+        module.unwrap().unwrap();
+    }
+
+    #[test]
+    fn reads_last_process_env_value() {
+        let spec: Value =
+            serde_json::from_str(r#"{"process": {"env": ["A=1", "B=x=y", "A=2"]}}"#).unwrap();
+        assert_eq!(process_env(&spec, "A").as_deref(), Some("2"));
+        assert_eq!(process_env(&spec, "B").as_deref(), Some("x=y"));
+        assert_eq!(process_env(&spec, "C"), None);
+    }
+
+    #[test]
+    fn rejects_bad_modules() {
+        let dir = std::env::temp_dir().join("shim-common-check-module");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("empty.wasm"), b"").unwrap();
+        std::fs::write(dir.join("bad.wasm"), b"\x7fELF").unwrap();
+        std::fs::write(dir.join("good.wasm"), b"\0asm\x01\0\0\0").unwrap();
+
+        let missing = check_module(&dir.join("missing.wasm"));
+        let empty = check_module(&dir.join("empty.wasm"));
+        let bad = check_module(&dir.join("bad.wasm"));
+        let good = check_module(&dir.join("good.wasm"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(missing.unwrap_err().to_string().contains("not found"));
+        assert!(empty.unwrap_err().to_string().contains("is empty"));
+        assert!(bad.unwrap_err().to_string().contains("not a Wasm binary"));
+        good.unwrap();
+    }
+}
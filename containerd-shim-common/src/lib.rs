@@ -0,0 +1,4 @@
+//! Helpers shared by the shims in this repository.
+
+pub mod bundle;
+pub mod process;
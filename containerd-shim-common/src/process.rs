@@ -0,0 +1,174 @@
+//! Setup shared by the engines, which run as pid 1 of the container and start
+//! the guest in-process.
+
+use std::future::Future;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Overrides the log level of the instance, e.g. `debug` or `trace`.
+pub const LOG_LEVEL_ENV: &str = "SHIM_LOG_LEVEL";
+
+/// File mode creation mask of the guest, in octal, e.g. `022`.
+pub const UMASK_ENV: &str = "SHIM_UMASK";
+
+/// Applies `SHIM_LOG_LEVEL` and `SHIM_UMASK` and publishes the hostname to the
+/// guest. Must run before the guest starts.
+///
+/// WASI has no call to read the hostname, so the name of the container's UTS
+/// namespace is passed as `HOSTNAME` unless `process.env` already sets it.
+/// libcontainer sets that name from the OCI `hostname`. Without one the
+/// namespace keeps the node's name, as the engine never learns the container
+/// id.
+pub fn init_process() -> Result<()> {
+    if let Some(level) = parse_log_level(std::env::var(LOG_LEVEL_ENV).ok())? {
+        log::set_max_level(level);
+    }
+    if let Some(umask) = parse_umask(std::env::var(UMASK_ENV).ok())? {
+        // SAFETY: umask only replaces the process' file mode creation mask.
+        unsafe { libc::umask(umask) };
+    }
+    if std::env::var_os("HOSTNAME").is_none() {
+        std::env::set_var("HOSTNAME", hostname()?);
+    }
+    Ok(())
+}
+
+/// Logs the full report of a failed run, with its context chain and the wasm
+/// backtrace of a trap; the error handed back to containerd only keeps the
+/// message. Goes to the shim log, never to the guest's stdio.
+pub fn log_failure(err: anyhow::Error) -> anyhow::Error {
+    log::error!(" >>> instance failed: {:?}", err);
+    err
+}
+
+/// Installs the SIGTERM and SIGINT handlers and returns a future that resolves
+/// with the first of them to arrive. Must be called inside a tokio runtime.
+///
+/// The engine runs as pid 1 of the container, so the kernel discards any
+/// signal without an installed handler apart from SIGKILL.
+pub fn shutdown_signal() -> Result<impl Future<Output = SignalKind>> {
+    let mut sigterm =
+        signal(SignalKind::terminate()).context("failed to install SIGTERM handler")?;
+    let mut sigint = signal(SignalKind::interrupt()).context("failed to install SIGINT handler")?;
+    Ok(async move {
+        tokio::select! {
+            _ = sigterm.recv() => SignalKind::terminate(),
+            _ = sigint.recv() => SignalKind::interrupt(),
+        }
+    })
+}
+
+/// Parses the environment variable `name`, if set.
+pub fn parse_env<T: FromStr>(name: &str) -> Result<Option<T>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    parse_value(name, std::env::var(name).ok())
+}
+
+/// Parses `value` of the setting `name`, ignoring surrounding whitespace.
+pub fn parse_value<T: FromStr>(name: &str, value: Option<String>) -> Result<Option<T>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    value
+        .map(|value| {
+            value
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid {name}: {value:?}"))
+        })
+        .transpose()
+}
+
+fn parse_log_level(value: Option<String>) -> Result<Option<log::LevelFilter>> {
+    value
+        .map(|level| {
+            level
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("invalid {LOG_LEVEL_ENV}: {level:?}"))
+        })
+        .transpose()
+}
+
+fn parse_umask(value: Option<String>) -> Result<Option<libc::mode_t>> {
+    value
+        .map(|umask| {
+            libc::mode_t::from_str_radix(umask.trim(), 8)
+                .ok()
+                .filter(|mask| *mask <= 0o777)
+                .ok_or_else(|| anyhow!("invalid {UMASK_ENV}: {umask:?}, expected octal 000-777"))
+        })
+        .transpose()
+}
+
+/// The hostname of the container's UTS namespace.
+fn hostname() -> Result<String> {
+    let mut buf = [0u8; 256];
+    // SAFETY: gethostname writes at most `buf.len()` bytes into `buf`.
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("failed to get hostname");
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_values() {
+        assert_eq!(parse_value::<u32>("N", None).unwrap(), None);
+        assert_eq!(
+            parse_value::<u32>("N", Some(" 8 ".to_string())).unwrap(),
+            Some(8)
+        );
+        let err = parse_value::<u32>("N", Some("many".to_string())).unwrap_err();
+        assert!(err.to_string().contains("invalid N"));
+    }
+
+    #[test]
+    fn parses_log_level() {
+        assert_eq!(parse_log_level(None).unwrap(), None);
+        assert_eq!(
+            parse_log_level(Some("DEBUG".to_string())).unwrap(),
+            Some(log::LevelFilter::Debug)
+        );
+        assert!(parse_log_level(Some("loud".to_string())).is_err());
+    }
+
+    #[test]
+    fn parses_umask() {
+        assert_eq!(parse_umask(None).unwrap(), None);
+        assert_eq!(parse_umask(Some("022".into())).unwrap(), Some(0o022));
+        assert_eq!(parse_umask(Some(" 0077".into())).unwrap(), Some(0o077));
+        assert!(parse_umask(Some("1000".into())).is_err());
+        assert!(parse_umask(Some("089".into())).is_err());
+        assert!(parse_umask(Some("rw-".into())).is_err());
+    }
+
+    #[test]
+    fn applies_umask_to_created_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join("shim-common-umask-test");
+        let umask = parse_umask(Some("027".into())).unwrap().unwrap();
+        let previous = unsafe { libc::umask(umask) };
+        let created = std::fs::File::create(&path);
+        unsafe { libc::umask(previous) };
+        created.unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o640);
+    }
+
+    #[test]
+    fn reads_hostname() {
+        let expected = std::fs::read_to_string("/proc/sys/kernel/hostname").unwrap();
+        assert_eq!(hostname().unwrap(), expected.trim_end());
+    }
+}
@@ -9,6 +9,7 @@ use clap::Args;
 
 use lunatic_distributed::DistributedProcessState;
 use lunatic_process::{
+    config::ProcessConfig,
     env::{Environment, LunaticEnvironment, LunaticEnvironments},
     runtimes::{wasmtime::WasmtimeRuntime, RawWasm},
     wasm::spawn_wasm,
@@ -23,6 +24,7 @@ pub struct RunWasm {
     pub path: PathBuf,
     pub wasm_args: Vec<String>,
     pub dir: Vec<PathBuf>,
+    pub max_memory: Option<usize>,
 
     pub runtime: WasmtimeRuntime,
     pub envs: Arc<LunaticEnvironments>,
@@ -50,6 +52,10 @@ pub async fn run_wasm(args: RunWasm) -> Result<()> {
     wasi_args.extend(args.wasm_args);
     config.set_command_line_arguments(wasi_args);
 
+    if let Some(max_memory) = args.max_memory {
+        config.set_max_memory(max_memory);
+    }
+
     // Inherit environment variables
     config.set_environment_variables(std::env::vars().collect());
 
@@ -99,8 +105,11 @@ pub async fn run_wasm(args: RunWasm) -> Result<()> {
         path.to_string_lossy()
     ))?;
 
-    // Wait on the main process to finish
-    task.await.map(|_| ()).map_err(|e| anyhow!(e.to_string()))
+    // Wait on the main process to finish, keeping the failure of the guest
+    task.await
+        .map_err(|e| anyhow!(e.to_string()))?
+        .map(|_| ())
+        .with_context(|| format!("{}::_start() failed", path.to_string_lossy()))
 }
 
 #[cfg(feature = "prometheus")]
@@ -1,8 +1,14 @@
 use anyhow::{Context, Result};
-use std::path::PathBuf;
+use std::future::Future;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::runtime::Runtime;
+use tokio::signal::unix::SignalKind;
 
+use containerd_shim_common::bundle::check_module;
+use containerd_shim_common::process::{init_process, log_failure, parse_env, shutdown_signal};
 use containerd_shim_wasm::container::{Engine, RuntimeContext, Stdio};
 use lunatic_process::{
     env::{Environments, LunaticEnvironments},
@@ -11,6 +17,24 @@ use lunatic_process::{
 
 use crate::common::{run_wasm, RunWasm};
 
+/// Seconds the workload may keep running after a shutdown signal before it is
+/// stopped forcibly. Unset or `0` stops it immediately.
+const KILL_GRACE_PERIOD_ENV: &str = "LUNATIC_KILL_GRACE_PERIOD_SECS";
+
+/// Exit code reported when the grace period elapsed, mirroring a SIGKILL.
+const KILLED_EXIT_CODE: i32 = 128 + 9;
+
+/// Seconds the workload may run in total before it is killed. Unset means no
+/// limit.
+const MAX_LIFETIME_ENV: &str = "LUNATIC_MAX_LIFETIME_SECS";
+
+/// Exit code reported when the maximum lifetime elapsed, as used by timeout(1).
+const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// Limit for the linear memory of each guest process, in bytes; `memory.grow`
+/// beyond it fails.
+const MAX_MEMORY_ENV: &str = "LUNATIC_MAX_MEMORY_BYTES";
+
 #[derive(Clone, Default)]
 pub struct LunaticEngine;
 
@@ -20,17 +44,128 @@ impl Engine for LunaticEngine {
     }
 
     fn run_wasi(&self, ctx: &impl RuntimeContext, stdio: Stdio) -> Result<i32> {
+        init_process()?;
         log::info!("setting up wasi");
         stdio.redirect()?;
-        let cmd = ctx.entrypoint().context("no cmd provided")?;
+        let cmd = ctx
+            .entrypoint()
+            .context("no cmd provided: process.args is empty")?;
+        check_module(cmd)?;
+        let args = guest_args(ctx.args());
+        let grace_period = parse_env(KILL_GRACE_PERIOD_ENV)?
+            .map(Duration::from_secs)
+            .unwrap_or_default();
+        let max_lifetime = parse_env(MAX_LIFETIME_ENV)?.map(Duration::from_secs);
+        let max_memory = parse_env(MAX_MEMORY_ENV)?;
+        let rt = Runtime::new().context("failed to create runtime")?;
+        rt.block_on(async {
+            run_until_shutdown(
+                with_max_lifetime(
+                    // Expose the container rootfs, including the OCI mounts
+                    // libcontainer has set up, to the guest. Read-only mounts
+                    // and paths escaping the rootfs are left to libcontainer:
+                    // the guest only sees the container's mount namespace.
+                    exec(cmd.to_owned(), args, vec![PathBuf::from("/")], max_memory),
+                    max_lifetime,
+                ),
+                shutdown_signal()?,
+                grace_period,
+            )
+            .await
+        })
+        .map_err(log_failure)
+    }
+}
+
+/// Runs the module at `source`, or read from `stdin` if `source` is `-`,
+/// outside of containerd. Meant for local development only, so the guest only
+/// gets the current directory preopened.
+pub fn run_module(source: &Path, args: Vec<String>, mut stdin: impl Read) -> Result<()> {
+    let (path, piped) = if source == Path::new("-") {
+        let mut module = Vec::new();
+        stdin
+            .read_to_end(&mut module)
+            .context("failed to read module from stdin")?;
+        let path = std::env::temp_dir().join(format!("lunatic-module-{}.wasm", std::process::id()));
+        std::fs::write(&path, module).with_context(|| format!("failed to write {path:?}"))?;
+        (path, true)
+    } else {
+        (source.to_owned(), false)
+    };
+
+    let res = check_module(&path).and_then(|_| {
         let rt = Runtime::new().context("failed to create runtime")?;
-        rt.block_on(exec(cmd.to_owned()))?;
-        Ok(0)
+        rt.block_on(exec(path.clone(), args, vec![], None))
+    });
+    if piped {
+        let _ = std::fs::remove_file(&path);
+    }
+    res
+}
+
+/// Arguments for the guest from `process.args`, which start with the module
+/// itself.
+fn guest_args(args: &[String]) -> Vec<String> {
+    args.iter().skip(1).cloned().collect()
+}
+
+/// Runs `task` to completion, or kills it once `max_lifetime` has elapsed.
+async fn with_max_lifetime(
+    task: impl Future<Output = Result<()>>,
+    max_lifetime: Option<Duration>,
+) -> Result<i32> {
+    let Some(max_lifetime) = max_lifetime else {
+        return task.await.map(|_| 0);
+    };
+    match tokio::time::timeout(max_lifetime, task).await {
+        Ok(res) => res.map(|_| 0),
+        Err(_) => {
+            log::info!(
+                " >>> maximum lifetime of {:?} elapsed, killing",
+                max_lifetime
+            );
+            Ok(TIMEOUT_EXIT_CODE)
+        }
     }
 }
 
-async fn exec(cmd: PathBuf) -> Result<()> {
-    log::info!(" >>> lunatic wasm binary: {:?}", cmd);
+/// Drives `task` until it completes or `shutdown` resolves, returning the exit
+/// code for the instance. After a shutdown signal the task is given
+/// `grace_period` to finish on its own before it is dropped. A task stopped
+/// right away reports `128 + signal`, as if the signal had terminated it.
+async fn run_until_shutdown(
+    task: impl Future<Output = Result<i32>>,
+    shutdown: impl Future<Output = SignalKind>,
+    grace_period: Duration,
+) -> Result<i32> {
+    tokio::pin!(task);
+    let kind = tokio::select! {
+        res = &mut task => return res,
+        kind = shutdown => kind,
+    };
+    log::info!(" >>> received {:?}, shutting down", kind);
+
+    if grace_period.is_zero() {
+        return Ok(128 + kind.as_raw_value());
+    }
+    match tokio::time::timeout(grace_period, task).await {
+        Ok(res) => res,
+        Err(_) => {
+            log::info!(" >>> grace period of {:?} elapsed, killing", grace_period);
+            Ok(KILLED_EXIT_CODE)
+        }
+    }
+}
+
+/// Runs `cmd` with `dirs` preopened for the guest in addition to the current
+/// directory.
+async fn exec(
+    cmd: PathBuf,
+    args: Vec<String>,
+    dirs: Vec<PathBuf>,
+    max_memory: Option<usize>,
+) -> Result<()> {
+    log::info!(" >>> lunatic wasm binary: {:?} {:?}", cmd, args);
     // Create wasmtime runtime
     let wasmtime_config = runtimes::wasmtime::default_config();
     let runtime = runtimes::wasmtime::WasmtimeRuntime::new(&wasmtime_config)?;
@@ -39,8 +174,9 @@ async fn exec(cmd: PathBuf) -> Result<()> {
     let env = envs.create(1).await;
     run_wasm(RunWasm {
         path: cmd,
-        wasm_args: vec![],
-        dir: vec![],
+        wasm_args: args,
+        dir: dirs,
+        max_memory,
         runtime,
         envs,
         env,
@@ -51,7 +187,7 @@ async fn exec(cmd: PathBuf) -> Result<()> {
 
 #[cfg(test)]
 mod tests {
-    use crate::engine::exec;
+    use super::*;
 
     #[tokio::test]
     async fn test() {
@@ -59,10 +195,173 @@ mod tests {
             "../images/lunatic/target/wasm32-wasi/release/wasi-hello-world.wasm"
                 .to_string()
                 .into(),
+            vec![],
+            vec![],
+            None,
         )
         .await
         {
             panic!("Problem opening the file: {:?}", error)
         }
     }
+
+    /// `_start` grows the memory by one page and traps if that fails.
+    fn grow_memory_module() -> Vec<u8> {
+        wat::parse_str(
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "_start")
+                    (if (i32.eq (memory.grow (i32.const 1)) (i32.const -1))
+                        (then unreachable))))"#,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn traps_at_max_memory() {
+        let dir = std::env::temp_dir().join("lunatic-max-memory");
+        std::fs::create_dir_all(&dir).unwrap();
+        let module = dir.join("grow.wasm");
+        std::fs::write(&module, grow_memory_module()).unwrap();
+
+        exec(module.clone(), vec![], vec![], None).await.unwrap();
+        exec(module.clone(), vec![], vec![], Some(2 * 65536))
+            .await
+            .unwrap();
+        let err = exec(module, vec![], vec![], Some(65536)).await.unwrap_err();
+        let report = format!("{:?}", log_failure(err));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(report.contains("grow.wasm::_start() failed"), "{report}");
+        assert!(report.contains("wasm backtrace"), "{report}");
+    }
+
+    /// `_start` reads the first bytes of `path` through the `/` preopen, fd 4
+    /// after the current directory at fd 3, and traps unless they are `hell`.
+    fn read_file_module(path: &str) -> Vec<u8> {
+        let path = path.trim_start_matches('/');
+        wat::parse_str(format!(
+            r#"(module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_read"
+                    (func $fd_read (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 64) "{path}")
+                (func (export "_start")
+                    (if (call $path_open (i32.const 4) (i32.const 0) (i32.const 64)
+                            (i32.const {len}) (i32.const 0) (i64.const 2) (i64.const 0)
+                            (i32.const 0) (i32.const 0))
+                        (then unreachable))
+                    (i32.store (i32.const 8) (i32.const 16))
+                    (i32.store (i32.const 12) (i32.const 4))
+                    (if (call $fd_read (i32.load (i32.const 0)) (i32.const 8) (i32.const 1)
+                            (i32.const 4))
+                        (then unreachable))
+                    (if (i32.ne (i32.load (i32.const 16)) (i32.const 0x6c6c6568))
+                        (then unreachable))))"#,
+            len = path.len(),
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn reads_files_through_root_preopen() {
+        let dir = std::env::temp_dir().join("lunatic-root-preopen");
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("hello.txt");
+        std::fs::write(&file, "hello").unwrap();
+        let module = dir.join("read.wasm");
+        std::fs::write(&module, read_file_module(file.to_str().unwrap())).unwrap();
+
+        let res = exec(module, vec![], vec![PathBuf::from("/")], None).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        res.unwrap();
+    }
+
+    #[test]
+    fn runs_module_from_stdin() {
+        let stdin = Path::new("-");
+        run_module(stdin, vec![], &grow_memory_module()[..]).unwrap();
+        assert!(run_module(stdin, vec![], &b"not wasm"[..]).is_err());
+    }
+
+    #[test]
+    fn forwards_args_after_module() {
+        let args = ["/app.wasm", "--flag", "value"].map(String::from);
+        assert_eq!(guest_args(&args), vec!["--flag", "value"]);
+        assert!(guest_args(&args[..1]).is_empty());
+    }
+
+    fn pending<T>() -> impl Future<Output = Result<T>> {
+        std::future::pending()
+    }
+
+    async fn terminate() -> SignalKind {
+        SignalKind::terminate()
+    }
+
+    #[tokio::test]
+    async fn stops_on_sigterm() {
+        let shutdown = shutdown_signal().unwrap();
+        // SAFETY: raise only sends SIGTERM to this process, which now handles it.
+        assert_eq!(unsafe { libc::raise(libc::SIGTERM) }, 0);
+        let code = tokio::time::timeout(
+            Duration::from_secs(5),
+            run_until_shutdown(pending::<i32>(), shutdown, Duration::ZERO),
+        )
+        .await
+        .expect("SIGTERM did not stop the task")
+        .unwrap();
+        assert_eq!(code, 128 + libc::SIGTERM);
+    }
+
+    #[tokio::test]
+    async fn stops_immediately_without_grace_period() {
+        let code = run_until_shutdown(pending::<i32>(), terminate(), Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(code, 143);
+
+        let interrupt = async { SignalKind::interrupt() };
+        let code = run_until_shutdown(pending::<i32>(), interrupt, Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(code, 130);
+    }
+
+    #[tokio::test]
+    async fn kills_task_ignoring_sigterm_after_grace_period() {
+        let code = run_until_shutdown(pending::<i32>(), terminate(), Duration::from_millis(10))
+            .await
+            .unwrap();
+        assert_eq!(code, KILLED_EXIT_CODE);
+    }
+
+    #[tokio::test]
+    async fn lets_task_finish_within_grace_period() {
+        let task = async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            Ok(0)
+        };
+        let code = run_until_shutdown(task, terminate(), Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(code, 0);
+    }
+
+    #[tokio::test]
+    async fn kills_task_at_max_lifetime() {
+        let code = with_max_lifetime(pending::<()>(), Some(Duration::from_millis(10)))
+            .await
+            .unwrap();
+        assert_eq!(code, TIMEOUT_EXIT_CODE);
+    }
+
+    #[tokio::test]
+    async fn lets_task_finish_within_max_lifetime() {
+        let code = with_max_lifetime(async { Ok(()) }, Some(Duration::from_secs(5)))
+            .await
+            .unwrap();
+        assert_eq!(code, 0);
+    }
 }
@@ -1,6 +1,8 @@
 use std::env;
+use std::path::Path;
 
 use containerd_shim::{parse, run};
+use containerd_shim_common::bundle::parse_validate;
 use containerd_shim_wasm::container::Instance;
 use containerd_shim_wasm::sandbox::ShimCli;
 
@@ -10,6 +12,7 @@ type LunaticInstance = Instance<LunaticEngine>;
 
 mod common;
 mod engine;
+mod validate;
 
 fn parse_version() {
     let os_args: Vec<_> = env::args_os().collect();
@@ -24,7 +27,38 @@ fn parse_version() {
     }
 }
 
+/// `--module <path|-> [args...]` runs a single module without containerd and
+/// exits with 0 once it finishes or 1 if it fails. Lunatic does not report the
+/// guest's own exit code.
+fn parse_module() {
+    let os_args: Vec<_> = env::args_os().collect();
+    if os_args.len() >= 3 && os_args[1] == "--module" {
+        let source = Path::new(&os_args[2]);
+        let args: Result<Vec<_>, _> = os_args[3..]
+            .iter()
+            .cloned()
+            .map(|arg| arg.into_string())
+            .collect();
+        let res = match args {
+            Ok(args) => engine::run_module(source, args, std::io::stdin()),
+            Err(arg) => Err(anyhow::anyhow!("argument {arg:?} is not valid UTF-8")),
+        };
+        match res {
+            Ok(()) => {
+                println!("{}: module finished", source.display());
+                std::process::exit(0);
+            }
+            Err(err) => {
+                eprintln!("{}: module failed: {:#}", source.display(), err);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
 fn main() {
+    parse_module();
+    parse_validate(validate::validate_bundle);
     parse_version();
     run::<ShimCli<LunaticInstance>>("io.containerd.lunatic.v1", None);
 }
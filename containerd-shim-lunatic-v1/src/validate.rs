@@ -0,0 +1,55 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use containerd_shim_common::bundle::{check_module, read_spec, resolve};
+
+/// Checks that the bundle at `bundle` can be started without running it:
+/// its `config.json` parses and the module named by `process.args` is a Wasm
+/// binary inside the rootfs. Relative modules are resolved against
+/// `process.cwd`, as they are when the container starts.
+pub fn validate_bundle(bundle: &Path) -> Result<()> {
+    let spec = read_spec(bundle)?;
+    let module = spec["process"]["args"][0]
+        .as_str()
+        .context("no cmd provided: process.args is empty")?;
+    check_module(&resolve(bundle, &spec, Path::new(module)))
+}
+
+#[cfg(test)]
+mod tests {
+    use containerd_shim_common::bundle::fixture_bundle;
+
+    use super::*;
+
+    const MODULE: &[u8] = b"\0asm\x01\0\0\0";
+
+    const CONFIG: &str = r#"{"root": {"path": "rootfs"}, "process": {"args": ["/app.wasm"]}}"#;
+
+    #[test]
+    fn accepts_valid_bundle() {
+        let bundle = fixture_bundle("lunatic-validate-valid", CONFIG, &[("app.wasm", MODULE)]);
+        let res = validate_bundle(&bundle);
+        std::fs::remove_dir_all(&bundle).unwrap();
+        res.unwrap();
+    }
+
+    #[test]
+    fn rejects_missing_module() {
+        let bundle = fixture_bundle("lunatic-validate-missing", CONFIG, &[]);
+        let res = validate_bundle(&bundle);
+        std::fs::remove_dir_all(&bundle).unwrap();
+        assert!(res.unwrap_err().to_string().contains("not found"));
+    }
+
+    #[test]
+    fn resolves_module_against_cwd() {
+        let bundle = fixture_bundle(
+            "lunatic-validate-cwd",
+            r#"{"root": {"path": "rootfs"}, "process": {"args": ["app.wasm"], "cwd": "/app"}}"#,
+            &[("app/app.wasm", MODULE)],
+        );
+        let res = validate_bundle(&bundle);
+        std::fs::remove_dir_all(&bundle).unwrap();
+        res.unwrap();
+    }
+}
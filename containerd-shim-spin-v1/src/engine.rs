@@ -1,21 +1,96 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use spin_trigger::TriggerHooks;
+use std::future::Future;
 use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
+use containerd_shim_common::process::{init_process, log_failure, parse_env, shutdown_signal};
 use containerd_shim_wasm::container::{Engine, RuntimeContext, Stdio};
 use log::info;
 use spin_manifest::Application;
 use spin_redis_engine::RedisTrigger;
 use spin_trigger::{loader, RuntimeConfig, TriggerExecutor, TriggerExecutorBuilder};
 use spin_trigger_http::HttpTrigger;
-use tokio::runtime::Runtime;
+use tokio::runtime::Builder;
+use tokio::signal::unix::SignalKind;
 use url::Url;
-use wasmtime::OptLevel;
+use wasmtime::{InstanceAllocationStrategy, OptLevel, PoolingAllocationConfig};
+
+use crate::validate::{check_components, check_manifest, manifest_path};
 
 const SPIN_ADDR: &str = "0.0.0.0:80";
 
+/// Overrides the address the HTTP trigger listens on, as in `spin up --listen`.
+const LISTEN_ADDR_ENV: &str = "SPIN_HTTP_LISTEN_ADDR";
+
+/// Path of the spin manifest, relative to the working directory unless
+/// absolute. Defaults to `spin.toml`.
+pub(crate) const MANIFEST_PATH_ENV: &str = "SPIN_MANIFEST_PATH";
+
+/// `none` gives components no stdio at all instead of the container's.
+const STDIO_ENV: &str = "SHIM_STDIO";
+
+/// Number of worker threads of the async runtime, defaults to one per CPU.
+const WORKER_THREADS_ENV: &str = "SPIN_WORKER_THREADS";
+
+/// Caps the linear memory of each component instance, in bytes. Growing past
+/// it fails inside the guest instead of consuming the container's memory.
+const MAX_MEMORY_BYTES_ENV: &str = "SPIN_MAX_MEMORY_BYTES";
+
+/// Directory of files providing application variables, each named after the
+/// variable it sets, e.g. a mounted Kubernetes secret.
+const VARIABLES_DIR_ENV: &str = "SPIN_VARIABLES_DIR";
+
+/// Prefix of the environment variables spin's default provider resolves
+/// application variables from.
+const VARIABLE_ENV_PREFIX: &str = "SPIN_CONFIG_";
+
+/// Limits for wasmtime's pooling instance allocator. Setting either enables it,
+/// otherwise spin's default allocation strategy is kept. The instance limit
+/// counts core wasm instances, and a spin component may instantiate several;
+/// the memory limit is in 64 KiB pages per linear memory.
+const POOLING_MAX_INSTANCES_ENV: &str = "SPIN_POOLING_MAX_INSTANCES";
+const POOLING_MAX_MEMORY_PAGES_ENV: &str = "SPIN_POOLING_MAX_MEMORY_PAGES";
+
+/// Comma separated `host_path:guest_path[:ro]` directories to preopen for
+/// every component.
+const PREOPENS_ENV: &str = "SPIN_PREOPENS";
+
+/// Overrides where spin persists key-value stores, `<working dir>/.spin` by
+/// default. An empty value keeps them in memory for the instance's lifetime.
+const STATE_DIR_ENV: &str = "SPIN_STATE_DIR";
+
+/// File backing the default SQLite database of the application, relative to
+/// the working directory unless absolute.
+const SQLITE_DATABASE_ENV: &str = "SPIN_SQLITE_DATABASE";
+
+/// Comma separated `feature=true|false` toggles applied to the wasmtime config,
+/// limited to the features in [`WASM_FEATURES`].
+const WASM_FEATURES_ENV: &str = "SPIN_WASMTIME_FEATURES";
+
+type FeatureToggle = fn(&mut wasmtime::Config, bool) -> &mut wasmtime::Config;
+
+const WASM_FEATURES: &[(&str, FeatureToggle)] = &[
+    ("simd", wasmtime::Config::wasm_simd),
+    ("relaxed_simd", wasmtime::Config::wasm_relaxed_simd),
+    ("reference_types", wasmtime::Config::wasm_reference_types),
+    ("bulk_memory", wasmtime::Config::wasm_bulk_memory),
+    ("multi_value", wasmtime::Config::wasm_multi_value),
+    ("multi_memory", wasmtime::Config::wasm_multi_memory),
+    ("threads", wasmtime::Config::wasm_threads),
+    ("memory64", wasmtime::Config::wasm_memory64),
+];
+
+/// Directory for wasmtime's compilation cache. Caching is disabled when unset.
+const WASMTIME_CACHE_DIR_ENV: &str = "SPIN_WASMTIME_CACHE_DIR";
+
+/// Path of an existing wasmtime cache config file, as used by `wasmtime
+/// --cache-config`. Mutually exclusive with `SPIN_WASMTIME_CACHE_DIR`.
+const WASMTIME_CACHE_CONFIG_ENV: &str = "WASMTIME_CACHE_CONFIG";
+
 #[derive(Clone, Default)]
 pub struct SpinEngine;
 struct StdioTriggerHook{}
@@ -35,6 +110,117 @@ impl TriggerHooks for StdioTriggerHook {
     }
 }
 
+/// Exposes the container's environment, i.e. the OCI `process.env`, to every
+/// component in addition to the environment declared in the manifest.
+/// Application variables meant for spin itself are not forwarded.
+struct EnvTriggerHook {
+    env: Vec<(String, String)>,
+}
+
+impl EnvTriggerHook {
+    fn from_process_env() -> Self {
+        Self::from_vars(std::env::vars())
+    }
+
+    fn from_vars(vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        Self {
+            env: vars
+                .into_iter()
+                .filter(|(k, _)| !k.starts_with(VARIABLE_ENV_PREFIX))
+                .collect(),
+        }
+    }
+}
+
+impl TriggerHooks for EnvTriggerHook {
+    fn component_store_builder(
+        &self,
+        _component: &spin_app::AppComponent,
+        builder: &mut spin_core::StoreBuilder,
+    ) -> Result<()> {
+        builder.env(self.env.iter().map(|(k, v)| (k, v)))
+    }
+}
+
+/// Makes additional host directories visible to every component.
+struct PreopenTriggerHook {
+    preopens: Vec<Preopen>,
+}
+
+impl TriggerHooks for PreopenTriggerHook {
+    fn component_store_builder(
+        &self,
+        _component: &spin_app::AppComponent,
+        builder: &mut spin_core::StoreBuilder,
+    ) -> Result<()> {
+        for preopen in &self.preopens {
+            if preopen.read_only {
+                builder.read_only_preopened_dir(&preopen.host, preopen.guest.clone())?;
+            } else {
+                builder.read_write_preopened_dir(&preopen.host, preopen.guest.clone())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Limits how far the linear memory of every component may grow.
+struct MemoryLimitTriggerHook {
+    max_memory_bytes: usize,
+}
+
+impl TriggerHooks for MemoryLimitTriggerHook {
+    fn component_store_builder(
+        &self,
+        _component: &spin_app::AppComponent,
+        builder: &mut spin_core::StoreBuilder,
+    ) -> Result<()> {
+        builder.max_memory_size(self.max_memory_bytes);
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Preopen {
+    host: PathBuf,
+    guest: PathBuf,
+    read_only: bool,
+}
+
+impl FromStr for Preopen {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (host, guest, read_only) = match s.split(':').collect::<Vec<_>>()[..] {
+            [host, guest] => (host, guest, false),
+            [host, guest, "ro"] => (host, guest, true),
+            _ => bail!("invalid preopen {s:?}: expected host_path:guest_path[:ro]"),
+        };
+        let (host, guest) = (PathBuf::from(host), PathBuf::from(guest));
+        if !host.is_dir() {
+            bail!("invalid preopen {s:?}: host path {host:?} is not a directory");
+        }
+        // component files are mounted at the guest root
+        if !guest.is_absolute() || guest == Path::new("/") {
+            bail!("invalid preopen {s:?}: guest path must be absolute and not /");
+        }
+        Ok(Self {
+            host,
+            guest,
+            read_only,
+        })
+    }
+}
+
+fn parse_preopens(value: Option<String>) -> Result<Vec<Preopen>> {
+    value
+        .iter()
+        .flat_map(|value| value.split(','))
+        .filter(|preopen| !preopen.trim().is_empty())
+        .map(|preopen| preopen.trim().parse())
+        .collect()
+}
+
 impl SpinEngine {
     async fn build_spin_application(
         mod_path: PathBuf,
@@ -55,61 +241,133 @@ impl SpinEngine {
         let locked_path = working_dir.join("spin.lock");
         let locked_app_contents =
             serde_json::to_vec_pretty(&locked_app).expect("could not serialize locked app");
-        std::fs::write(&locked_path, locked_app_contents).expect("could not write locked app");
+        // spin loads the application from a lock file next to the manifest, so
+        // the working directory must stay writable
+        std::fs::write(&locked_path, locked_app_contents).with_context(|| {
+            format!("could not write locked app {locked_path:?}, is the rootfs read-only?")
+        })?;
         let locked_url = Url::from_file_path(&locked_path)
             .map_err(|_| anyhow!("cannot convert to file URL: {locked_path:?}"))?
             .to_string();
 
         // Build trigger config
         let loader = loader::TriggerLoader::new(working_dir.clone(), true);
-        let runtime_config = RuntimeConfig::new(PathBuf::from("/").into());
+        let runtime_config = runtime_config(
+            working_dir,
+            std::env::var(STATE_DIR_ENV).ok(),
+            std::env::var_os(SQLITE_DATABASE_ENV).map(PathBuf::from),
+        )?;
         let mut builder = TriggerExecutorBuilder::new(loader);
+        if inherit_stdio(std::env::var(STDIO_ENV).ok())? {
+            builder.hooks(StdioTriggerHook {});
+        }
+        builder.hooks(EnvTriggerHook::from_process_env());
+        builder.hooks(PreopenTriggerHook {
+            preopens: parse_preopens(std::env::var(PREOPENS_ENV).ok())?,
+        });
+        if let Some(max_memory_bytes) = parse_env(MAX_MEMORY_BYTES_ENV)? {
+            info!(" >>> limiting guest memory to {} bytes", max_memory_bytes);
+            builder.hooks(MemoryLimitTriggerHook { max_memory_bytes });
+        }
         builder
             .config_mut()
             .wasmtime_config()
             .cranelift_opt_level(OptLevel::Speed);
+        if let Ok(features) = std::env::var(WASM_FEATURES_ENV) {
+            info!(" >>> setting wasmtime features {}", features);
+            apply_wasm_features(builder.config_mut().wasmtime_config(), &features)?;
+        }
+        let pooling = PoolingLimits::from_env()?;
+        if pooling.is_enabled() {
+            info!(" >>> using pooling allocator {:?}", pooling);
+            pooling.apply(builder.config_mut().wasmtime_config());
+        }
+        let cache_dir = std::env::var_os(WASMTIME_CACHE_DIR_ENV);
+        let cache_config = std::env::var_os(WASMTIME_CACHE_CONFIG_ENV);
+        match (&cache_dir, &cache_config) {
+            (Some(_), Some(_)) => bail!(
+                "{WASMTIME_CACHE_DIR_ENV} and {WASMTIME_CACHE_CONFIG_ENV} are mutually exclusive"
+            ),
+            (Some(cache_dir), None) => {
+                info!(" >>> using wasmtime cache in {:?}", cache_dir);
+                enable_cache(builder.config_mut().wasmtime_config(), Path::new(cache_dir))?;
+            }
+            (None, Some(cache_config)) => {
+                info!(" >>> using wasmtime cache config {:?}", cache_config);
+                load_cache_config(
+                    builder.config_mut().wasmtime_config(),
+                    Path::new(cache_config),
+                )?;
+            }
+            (None, None) => {}
+        }
         let init_data = Default::default();
-        let executor = builder.build(locked_url, runtime_config, init_data).await?;
+        let (executor, elapsed) = timed(builder.build(locked_url, runtime_config, init_data)).await;
+        let executor = executor?;
+        info!(
+            " >>> spin trigger built: elapsed_ms={} cache_configured={}",
+            elapsed.as_millis(),
+            cache_dir.is_some() || cache_config.is_some()
+        );
         Ok(executor)
     }
 
     async fn wasm_exec_async(&self) -> Result<()> {
-        info!(" >>> building spin application");
-        let app =
-            SpinEngine::build_spin_application(PathBuf::from("/spin.toml"), PathBuf::from("/"))
-                .await
-                .context("failed to build spin application")?;
+        // libcontainer has already changed into the OCI process.cwd
+        let working_dir = std::env::current_dir().context("failed to get working directory")?;
+        info!(" >>> building spin application in {:?}", working_dir);
+        let manifest = manifest_path(&working_dir, std::env::var(MANIFEST_PATH_ENV).ok());
+        check_manifest(&manifest)?;
+        let (app, elapsed) = timed(SpinEngine::build_spin_application(
+            manifest,
+            working_dir.clone(),
+        ))
+        .await;
+        let app = app.context("failed to build spin application")?;
+        check_components(&app)?;
+        info!(
+            " >>> spin application loaded: elapsed_ms={} components={}",
+            elapsed.as_millis(),
+            app.components.len()
+        );
 
         let trigger = app.info.trigger.clone();
         info!(" >>> building spin trigger {:?}", trigger);
 
         let f = match trigger {
             spin_manifest::ApplicationTrigger::Http(_config) => {
-                let http_trigger: HttpTrigger =
-                    SpinEngine::build_spin_trigger(PathBuf::from("/"), app)
-                        .await
-                        .context("failed to build spin trigger")?;
-                info!(" >>> running spin trigger");
+                let http_trigger: HttpTrigger = SpinEngine::build_spin_trigger(working_dir, app)
+                    .await
+                    .context("failed to build spin trigger")?;
+                let address = listen_addr(std::env::var(LISTEN_ADDR_ENV).ok())?;
+                ensure_addr_available(address)?;
+                info!(" >>> running spin trigger on {}", address);
                 http_trigger.run(spin_trigger_http::CliArgs {
-                    address: parse_addr(SPIN_ADDR).unwrap(),
+                    address,
                     tls_cert: None,
                     tls_key: None,
                 })
             }
             spin_manifest::ApplicationTrigger::Redis(_config) => {
-                let redis_trigger: RedisTrigger =
-                    SpinEngine::build_spin_trigger(PathBuf::from("/"), app)
-                        .await
-                        .context("failed to build spin trigger")?;
+                let redis_trigger: RedisTrigger = SpinEngine::build_spin_trigger(working_dir, app)
+                    .await
+                    .context("failed to build spin trigger")?;
 
                 info!(" >>> running spin trigger");
                 redis_trigger.run(spin_trigger::cli::NoArgs)
             }
-            _ => todo!("Only Http and Redis triggers are currently supported."),
+            other => {
+                let components: Vec<_> = app.components.iter().map(|c| c.id.as_str()).collect();
+                bail!(
+                    "unsupported trigger {:?} for components {:?}: only http and redis triggers are supported",
+                    other,
+                    components
+                )
+            }
         };
 
         info!(" >>> notifying main thread we are about to start");
-        f.await
+        run_until_shutdown(f, shutdown_signal()?).await
     }
 }
 
@@ -119,11 +377,19 @@ impl Engine for SpinEngine {
     }
 
     fn run_wasi(&self, _ctx: &impl RuntimeContext, stdio: Stdio) -> Result<i32> {
+        init_process()?;
         info!("setting up wasi");
         stdio.redirect()?;
-        let rt = Runtime::new().context("failed to create runtime")?;
+        if let Some(dir) = std::env::var_os(VARIABLES_DIR_ENV) {
+            for (key, value) in read_variables_dir(Path::new(&dir))? {
+                std::env::set_var(key, value);
+            }
+        }
+        let rt = runtime_builder(parse_env(WORKER_THREADS_ENV)?)?
+            .build()
+            .context("failed to create runtime")?;
 
-        rt.block_on(self.wasm_exec_async())?;
+        rt.block_on(self.wasm_exec_async()).map_err(log_failure)?;
         Ok(0)
     }
 
@@ -132,6 +398,41 @@ impl Engine for SpinEngine {
     }
 }
 
+fn runtime_builder(worker_threads: Option<usize>) -> Result<Builder> {
+    let mut builder = Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(n) = worker_threads {
+        if n < 1 {
+            bail!("{WORKER_THREADS_ENV} must be at least 1");
+        }
+        builder.worker_threads(n);
+    }
+    Ok(builder)
+}
+
+/// Awaits `fut`, returning its output along with how long it took.
+async fn timed<T>(fut: impl Future<Output = T>) -> (T, Duration) {
+    let start = Instant::now();
+    let output = fut.await;
+    (output, start.elapsed())
+}
+
+/// Drives `trigger` until it completes or `shutdown` resolves, which stops the
+/// instance cleanly. Requests still in flight are dropped: spin's HTTP trigger
+/// cannot stop accepting connections, so there is no way to drain it.
+async fn run_until_shutdown(
+    trigger: impl Future<Output = Result<()>>,
+    shutdown: impl Future<Output = SignalKind>,
+) -> Result<()> {
+    tokio::select! {
+        res = trigger => res,
+        kind = shutdown => {
+            info!(" >>> received {:?}, shutting down", kind);
+            Ok(())
+        }
+    }
+}
+
 fn parse_addr(addr: &str) -> Result<SocketAddr> {
     let addrs: SocketAddr = addr
         .to_socket_addrs()?
@@ -140,9 +441,216 @@ fn parse_addr(addr: &str) -> Result<SocketAddr> {
     Ok(addrs)
 }
 
+fn inherit_stdio(value: Option<String>) -> Result<bool> {
+    match value.as_deref().map(str::trim) {
+        None | Some("") | Some("inherit") => Ok(true),
+        Some("none") => Ok(false),
+        Some(other) => bail!("invalid {STDIO_ENV}: {other:?}, expected inherit or none"),
+    }
+}
+
+fn listen_addr(value: Option<String>) -> Result<SocketAddr> {
+    parse_addr(value.as_deref().unwrap_or(SPIN_ADDR))
+}
+
+/// Probes `addr` so that a port collision is reported as such, rather than as
+/// a generic failure from inside the trigger.
+fn ensure_addr_available(addr: SocketAddr) -> Result<()> {
+    match std::net::TcpListener::bind(addr) {
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::AddrInUse => {
+            bail!("cannot listen on {addr}: address is already in use")
+        }
+        Err(err) => Err(err).with_context(|| format!("cannot listen on {addr}")),
+    }
+}
+
+/// Reads application variables from the files in `dir`. Variables without a
+/// file stay unset.
+fn read_variables_dir(dir: &Path) -> Result<Vec<(String, String)>> {
+    let entries = std::fs::read_dir(dir)
+        .with_context(|| format!("failed to read variables directory {dir:?}"))?;
+    let mut variables = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        // skip hidden entries such as the `..data` links of Kubernetes volumes
+        if name.starts_with('.') || !path.is_file() {
+            continue;
+        }
+        let value = match std::fs::read_to_string(&path) {
+            Ok(value) => value,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read variable {path:?}"))
+            }
+        };
+        let key = format!("{VARIABLE_ENV_PREFIX}{}", name.to_uppercase());
+        variables.push((key, value));
+    }
+    variables.sort();
+    Ok(variables)
+}
+
+fn runtime_config(
+    working_dir: PathBuf,
+    state_dir: Option<String>,
+    sqlite_database: Option<PathBuf>,
+) -> Result<RuntimeConfig> {
+    let mut runtime_config = RuntimeConfig::new(Some(working_dir.clone()));
+    if let Some(database) = sqlite_database {
+        let database = working_dir.join(database);
+        ensure_writable(&database)?;
+        info!(" >>> using sqlite database {:?}", database);
+        // Spin only takes the database from a runtime config file. Generate
+        // one next to the database, which was just checked to be writable, so
+        // an existing `runtime-config.toml` is left alone and neither a
+        // read-only working directory nor a missing `/tmp` gets in the way. It
+        // is parsed on merge, so it is removed right away.
+        let config_path =
+            database.with_file_name(format!(".spin-runtime-config-{}.toml", std::process::id()));
+        let contents = format!(
+            "[sqlite_database.default]\ntype = \"spin\"\npath = {}\n",
+            serde_json::to_string(&database)?
+        );
+        std::fs::write(&config_path, contents)
+            .with_context(|| format!("failed to write runtime config {config_path:?}"))?;
+        let merged = runtime_config.merge_config_file(config_path.clone());
+        let _ = std::fs::remove_file(&config_path);
+        merged?;
+    }
+    if let Some(state_dir) = state_dir {
+        if !state_dir.is_empty() {
+            std::fs::create_dir_all(&state_dir)
+                .with_context(|| format!("failed to create state directory {state_dir:?}"))?;
+        }
+        runtime_config.set_state_dir(state_dir);
+    }
+    match runtime_config.state_dir() {
+        Some(state_dir) => info!(" >>> persisting state in {:?}", state_dir),
+        None => info!(" >>> keeping state in memory"),
+    }
+    Ok(runtime_config)
+}
+
+/// Creates the file at `path` if it is missing and checks that it can be
+/// written to, e.g. that it is not on a read-only mount.
+fn ensure_writable(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create directory for {path:?}"))?;
+    }
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("{path:?} is not writable"))?;
+    Ok(())
+}
+
+/// Applies the `feature=true|false` toggles in `features` to `config`, failing
+/// without changes if any of them is invalid.
+fn apply_wasm_features(config: &mut wasmtime::Config, features: &str) -> Result<()> {
+    let toggles = features
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (name, enabled) = entry.split_once('=').with_context(|| {
+                format!("invalid {WASM_FEATURES_ENV} entry {entry:?}, expected feature=true|false")
+            })?;
+            let (_, toggle) = WASM_FEATURES
+                .iter()
+                .find(|(feature, _)| *feature == name.trim())
+                .with_context(|| {
+                    let supported: Vec<_> = WASM_FEATURES.iter().map(|(f, _)| *f).collect();
+                    format!("unsupported wasmtime feature {name:?}, expected one of {supported:?}")
+                })?;
+            let enabled: bool = enabled
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid {WASM_FEATURES_ENV} entry {entry:?}"))?;
+            Ok((toggle, enabled))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    for (toggle, enabled) in toggles {
+        toggle(config, enabled);
+    }
+    Ok(())
+}
+
+/// Number of 64 KiB pages addressable by a 32-bit linear memory.
+const MAX_WASM32_PAGES: u64 = 1 << 16;
+
+#[derive(Debug, Default)]
+struct PoolingLimits {
+    max_instances: Option<u32>,
+    max_memory_pages: Option<u64>,
+}
+
+impl PoolingLimits {
+    fn from_env() -> Result<Self> {
+        Ok(Self {
+            max_instances: parse_env(POOLING_MAX_INSTANCES_ENV)?,
+            max_memory_pages: parse_env(POOLING_MAX_MEMORY_PAGES_ENV)?,
+        })
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.max_instances.is_some() || self.max_memory_pages.is_some()
+    }
+
+    fn apply(&self, config: &mut wasmtime::Config) {
+        let mut pooling = PoolingAllocationConfig::default();
+        if let Some(max_instances) = self.max_instances {
+            pooling.instance_count(max_instances);
+        }
+        // wasmtime defaults to 160 pages (10 MiB). Without an explicit limit
+        // memories may grow to the full 4 GiB, as without the pooling allocator.
+        pooling.instance_memory_pages(self.max_memory_pages.unwrap_or(MAX_WASM32_PAGES));
+        config.allocation_strategy(InstanceAllocationStrategy::Pooling(pooling));
+    }
+}
+
+/// Enables wasmtime's compilation cache in `cache_dir`, so that modules are
+/// only compiled on the first start. Cache entries are keyed by the module
+/// bytes, the wasmtime version and its configuration.
+fn enable_cache(config: &mut wasmtime::Config, cache_dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(cache_dir)
+        .with_context(|| format!("failed to create cache directory {cache_dir:?}"))?;
+    let config_path = cache_dir.join("wasmtime-cache.toml");
+    let contents = format!(
+        "[cache]\nenabled = true\ndirectory = {}\n",
+        serde_json::to_string(cache_dir)?
+    );
+    std::fs::write(&config_path, contents)
+        .with_context(|| format!("failed to write cache config {config_path:?}"))?;
+    config
+        .cache_config_load(&config_path)
+        .with_context(|| format!("failed to load cache config {config_path:?}"))?;
+    Ok(())
+}
+
+/// Enables wasmtime's compilation cache as configured by the cache config file
+/// at `path`.
+fn load_cache_config(config: &mut wasmtime::Config, path: &Path) -> Result<()> {
+    if !path.is_file() {
+        bail!("wasmtime cache config {path:?} not found");
+    }
+    config
+        .cache_config_load(path)
+        .with_context(|| format!("invalid wasmtime cache config {path:?}"))?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
 
     #[test]
     fn can_parse_spin_address() {
@@ -150,4 +658,370 @@ mod tests {
         assert_eq!(parsed.clone().port(), 80);
         assert_eq!(parsed.ip().to_string(), "0.0.0.0");
     }
+
+    #[test]
+    fn builds_runtime_with_worker_threads() {
+        let started = Arc::new(AtomicUsize::new(0));
+        let counter = started.clone();
+        let rt = runtime_builder(Some(2))
+            .unwrap()
+            .on_thread_start(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            while started.load(Ordering::SeqCst) < 2 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        });
+        assert_eq!(started.load(Ordering::SeqCst), 2);
+
+        assert!(runtime_builder(Some(0)).is_err());
+        assert!(runtime_builder(None).is_ok());
+    }
+
+    #[tokio::test]
+    async fn can_time_futures() {
+        let delay = Duration::from_millis(20);
+        let (output, elapsed) = timed(async {
+            tokio::time::sleep(delay).await;
+            42
+        })
+        .await;
+        assert_eq!(output, 42);
+        assert!(elapsed >= delay, "{elapsed:?}");
+    }
+
+    async fn interrupt() -> SignalKind {
+        SignalKind::interrupt()
+    }
+
+    #[tokio::test]
+    async fn stops_on_shutdown_signal() {
+        let trigger = std::future::pending::<Result<()>>();
+        run_until_shutdown(trigger, interrupt()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn returns_trigger_failure() {
+        let trigger = async { Err(anyhow!("listener failed")) };
+        let shutdown = std::future::pending::<SignalKind>();
+        let err = run_until_shutdown(trigger, shutdown).await.unwrap_err();
+        assert_eq!(err.to_string(), "listener failed");
+    }
+
+    #[test]
+    fn can_disable_stdio() {
+        assert!(inherit_stdio(None).unwrap());
+        assert!(inherit_stdio(Some("inherit".to_string())).unwrap());
+        assert!(!inherit_stdio(Some("none".to_string())).unwrap());
+        assert!(inherit_stdio(Some("null".to_string())).is_err());
+    }
+
+    #[test]
+    fn can_override_listen_address() {
+        assert_eq!(listen_addr(None).unwrap(), parse_addr(SPIN_ADDR).unwrap());
+        let parsed = listen_addr(Some("127.0.0.1:3000".to_string())).unwrap();
+        assert_eq!(parsed.port(), 3000);
+        assert_eq!(parsed.ip().to_string(), "127.0.0.1");
+    }
+
+    #[test]
+    fn reports_address_in_use() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let err = ensure_addr_available(listener.local_addr().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("already in use"));
+    }
+
+    #[test]
+    fn can_read_variables_dir() {
+        let dir = std::env::temp_dir().join("containerd-shim-spin-variables-test");
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        std::fs::write(dir.join("api_key"), "secret").unwrap();
+        std::fs::write(dir.join("region"), "westus").unwrap();
+        std::fs::write(dir.join("..data"), "ignored").unwrap();
+
+        let variables = read_variables_dir(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            variables,
+            vec![
+                ("SPIN_CONFIG_API_KEY".to_string(), "secret".to_string()),
+                ("SPIN_CONFIG_REGION".to_string(), "westus".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn pooling_allocator_keeps_memory_unlimited_by_default() {
+        let limits = PoolingLimits {
+            max_instances: Some(1),
+            max_memory_pages: None,
+        };
+        let mut config = wasmtime::Config::new();
+        limits.apply(&mut config);
+        let engine = wasmtime::Engine::new(&config).unwrap();
+        let module =
+            wasmtime::Module::new(&engine, r#"(module (memory (export "memory") 1))"#).unwrap();
+        let mut store = wasmtime::Store::new(&engine, ());
+        let instance = wasmtime::Instance::new(&mut store, &module, &[]).unwrap();
+        let memory = instance.get_memory(&mut store, "memory").unwrap();
+        memory.grow(&mut store, 200).unwrap();
+    }
+
+    #[test]
+    fn pooling_allocator_enforces_instance_limit() {
+        let limits = PoolingLimits {
+            max_instances: Some(1),
+            max_memory_pages: Some(1),
+        };
+        assert!(limits.is_enabled());
+        assert!(!PoolingLimits::default().is_enabled());
+
+        let mut config = wasmtime::Config::new();
+        limits.apply(&mut config);
+        let engine = wasmtime::Engine::new(&config).unwrap();
+        let module = wasmtime::Module::new(&engine, "(module (memory 1))").unwrap();
+
+        let mut first = wasmtime::Store::new(&engine, ());
+        wasmtime::Instance::new(&mut first, &module, &[]).unwrap();
+        let mut second = wasmtime::Store::new(&engine, ());
+        assert!(wasmtime::Instance::new(&mut second, &module, &[]).is_err());
+    }
+
+    #[test]
+    fn can_parse_preopens() {
+        let host = std::env::temp_dir();
+        let preopens = parse_preopens(Some(format!(
+            "{}:/mnt, {}:/data:ro",
+            host.display(),
+            host.display()
+        )))
+        .unwrap();
+        assert_eq!(
+            preopens,
+            vec![
+                Preopen {
+                    host: host.clone(),
+                    guest: PathBuf::from("/mnt"),
+                    read_only: false,
+                },
+                Preopen {
+                    host,
+                    guest: PathBuf::from("/data"),
+                    read_only: true,
+                },
+            ]
+        );
+        assert!(parse_preopens(None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_preopens() {
+        let host = std::env::temp_dir();
+        for preopen in [
+            "/does/not/exist:/mnt".to_string(),
+            format!("{}:/", host.display()),
+            format!("{}:mnt", host.display()),
+            format!("{}:/mnt:rw", host.display()),
+        ] {
+            assert!(preopen.parse::<Preopen>().is_err(), "{preopen}");
+        }
+    }
+
+    #[test]
+    fn can_select_state_dir() {
+        let working_dir = PathBuf::from("/app");
+        let default = runtime_config(working_dir.clone(), None, None).unwrap();
+        assert_eq!(default.state_dir(), Some(working_dir.join(".spin")));
+
+        let in_memory = runtime_config(working_dir.clone(), Some(String::new()), None).unwrap();
+        assert_eq!(in_memory.state_dir(), None);
+
+        let state_dir = std::env::temp_dir().join("containerd-shim-spin-state-test");
+        let persistent =
+            runtime_config(working_dir, Some(state_dir.display().to_string()), None).unwrap();
+        assert!(state_dir.is_dir());
+        std::fs::remove_dir_all(&state_dir).unwrap();
+        assert_eq!(persistent.state_dir(), Some(state_dir));
+    }
+
+    #[test]
+    fn can_configure_sqlite_database() {
+        let working_dir = std::env::temp_dir().join("containerd-shim-spin-sqlite-test");
+        assert_sqlite_database_works(&working_dir);
+    }
+
+    #[test]
+    fn can_configure_sqlite_database_without_temp_dir() {
+        // Child mode: rerun with TMPDIR pointing nowhere, as in a `FROM
+        // scratch` image, without affecting the tests running in parallel.
+        const WORKING_DIR_ENV: &str = "SPIN_SQLITE_TEST_WORKING_DIR";
+        if let Some(working_dir) = std::env::var_os(WORKING_DIR_ENV) {
+            assert!(!std::env::temp_dir().exists());
+            assert_sqlite_database_works(Path::new(&working_dir));
+            return;
+        }
+        let working_dir = std::env::temp_dir().join("containerd-shim-spin-sqlite-no-tmp-test");
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "engine::tests::can_configure_sqlite_database_without_temp_dir",
+            ])
+            .env(WORKING_DIR_ENV, &working_dir)
+            .env("TMPDIR", "/nonexistent/tmp")
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    /// Configures `data/app.db` in `working_dir` and checks that rows written
+    /// to it can be read back and that only the database was left behind.
+    fn assert_sqlite_database_works(working_dir: &Path) {
+        std::fs::create_dir_all(working_dir).unwrap();
+        let res = runtime_config(working_dir.to_owned(), None, Some("data/app.db".into()));
+        let database = working_dir.join("data/app.db");
+        let rows = database_rows(&database);
+        let entries = |dir: &Path| -> Vec<_> {
+            std::fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect()
+        };
+        let app_entries = entries(working_dir);
+        let data_entries = entries(&working_dir.join("data"));
+        std::fs::remove_dir_all(working_dir).unwrap();
+
+        res.unwrap();
+        assert_eq!(rows.unwrap(), 1);
+        assert_eq!(app_entries, ["data"], "runtime config written to the app");
+        assert_eq!(data_entries, ["app.db"], "runtime config not removed");
+    }
+
+    /// Writes a row to the sqlite database at `path` and counts the rows read
+    /// back over a new connection.
+    fn database_rows(path: &Path) -> Result<usize> {
+        use spin_sqlite::Connection;
+        use spin_sqlite_inproc::{InProcConnection, InProcDatabaseLocation};
+
+        let open = || {
+            InProcConnection::new(InProcDatabaseLocation::Path(path.to_owned()))
+                .map_err(|err| anyhow!("{err:?}"))
+        };
+        let rt = Builder::new_current_thread().build()?;
+        rt.block_on(async {
+            open()?
+                .execute_batch(
+                    "CREATE TABLE todos (title TEXT); INSERT INTO todos VALUES ('write');",
+                )
+                .await?;
+            let result = open()?
+                .query("SELECT title FROM todos", vec![])
+                .await
+                .map_err(|err| anyhow!("{err:?}"))?;
+            anyhow::Ok(result.rows.len())
+        })
+    }
+
+    #[test]
+    fn rejects_unwritable_sqlite_database() {
+        let file = std::env::temp_dir().join("containerd-shim-spin-not-a-dir");
+        std::fs::write(&file, b"").unwrap();
+        let err = ensure_writable(&file.join("app.db")).unwrap_err();
+        std::fs::remove_file(&file).unwrap();
+        assert!(err.to_string().contains("app.db"));
+    }
+
+    #[test]
+    fn can_toggle_wasm_features() {
+        const SIMD_MODULE: &str = "(module (func (result v128) v128.const i64x2 0 0))";
+        for (features, compiles) in [("simd=true", true), ("simd=false", false)] {
+            let mut config = wasmtime::Config::new();
+            apply_wasm_features(&mut config, features).unwrap();
+            let engine = wasmtime::Engine::new(&config).unwrap();
+            assert_eq!(
+                wasmtime::Module::new(&engine, SIMD_MODULE).is_ok(),
+                compiles,
+                "{features}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_wasm_features() {
+        let mut config = wasmtime::Config::new();
+        let err = apply_wasm_features(&mut config, "simd=true, gc=true").unwrap_err();
+        assert!(err.to_string().contains("unsupported wasmtime feature"));
+        assert!(apply_wasm_features(&mut config, "simd").is_err());
+        assert!(apply_wasm_features(&mut config, "simd=maybe").is_err());
+    }
+
+    fn count_files(dir: &Path) -> usize {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .map(|path| if path.is_dir() { count_files(&path) } else { 1 })
+            .sum()
+    }
+
+    #[test]
+    fn can_enable_cache() {
+        let cache_dir = std::env::temp_dir().join("containerd-shim-spin-cache-test");
+        let mut config = wasmtime::Config::new();
+        enable_cache(&mut config, &cache_dir).unwrap();
+        let config_only = count_files(&cache_dir);
+
+        let engine = wasmtime::Engine::new(&config).unwrap();
+        wasmtime::Module::new(&engine, "(module)").unwrap();
+        let cached = count_files(&cache_dir);
+        wasmtime::Module::new(&engine, "(module)").unwrap();
+        let cached_again = count_files(&cache_dir);
+        std::fs::remove_dir_all(cache_dir).unwrap();
+        assert!(cached > config_only, "module was not written to the cache");
+        assert_eq!(cached, cached_again, "module was compiled twice");
+    }
+
+    #[test]
+    fn can_load_cache_config() {
+        let dir = std::env::temp_dir().join("containerd-shim-spin-cache-config-test");
+        let cache_dir = dir.join("cache");
+        std::fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("wasmtime-cache.toml");
+        std::fs::write(
+            &config_path,
+            format!(
+                "[cache]\nenabled = true\ndirectory = {}\n",
+                serde_json::to_string(&cache_dir).unwrap()
+            ),
+        )
+        .unwrap();
+        let mut config = wasmtime::Config::new();
+        load_cache_config(&mut config, &config_path).unwrap();
+
+        let engine = wasmtime::Engine::new(&config).unwrap();
+        wasmtime::Module::new(&engine, "(module)").unwrap();
+        let cached = count_files(&cache_dir);
+        wasmtime::Module::new(&engine, "(module)").unwrap();
+        let cached_again = count_files(&cache_dir);
+
+        std::fs::write(&config_path, "[cache\nenabled = true").unwrap();
+        let malformed = load_cache_config(&mut wasmtime::Config::new(), &config_path);
+        let missing = load_cache_config(&mut wasmtime::Config::new(), &dir.join("missing.toml"));
+        std::fs::remove_dir_all(dir).unwrap();
+        assert!(cached > 0, "module was not written to the cache");
+        assert_eq!(cached, cached_again, "module was compiled twice");
+        assert!(malformed.is_err());
+        assert!(missing.unwrap_err().to_string().contains("not found"));
+    }
+
+    #[test]
+    fn can_forward_process_env() {
+        let hook = EnvTriggerHook::from_vars([
+            ("FOO".to_string(), "bar".to_string()),
+            (format!("{VARIABLE_ENV_PREFIX}TOKEN"), "secret".to_string()),
+        ]);
+        assert_eq!(hook.env, vec![("FOO".to_string(), "bar".to_string())]);
+    }
 }
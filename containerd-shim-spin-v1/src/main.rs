@@ -1,11 +1,13 @@
 use std::env;
 
 use containerd_shim as shim;
+use containerd_shim_common::bundle::parse_validate;
 use containerd_shim_wasm::container::Instance;
 use containerd_shim_wasm::sandbox::ShimCli;
 use engine::SpinEngine;
 
 mod engine;
+mod validate;
 
 pub type SpinInstance = Instance<SpinEngine>;
 
@@ -22,6 +24,7 @@ fn parse_version() {
 }
 
 fn main() {
+    parse_validate(validate::validate_bundle);
     parse_version();
     shim::run::<ShimCli<SpinInstance>>("io.containerd.spin.v1", None);
 }
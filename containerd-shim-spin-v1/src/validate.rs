@@ -0,0 +1,189 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use containerd_shim_common::bundle::{check_module, process_cwd, process_env, read_spec, resolve};
+
+use crate::engine::MANIFEST_PATH_ENV;
+
+/// Checks that the bundle at `bundle` can be started without running it:
+/// its `config.json` parses, the spin manifest loads and every component source
+/// is a Wasm binary.
+pub fn validate_bundle(bundle: &Path) -> Result<()> {
+    let spec = read_spec(bundle)?;
+    let manifest = manifest_path(
+        Path::new(process_cwd(&spec)),
+        process_env(&spec, MANIFEST_PATH_ENV),
+    );
+    let manifest = resolve(bundle, &spec, &manifest);
+    check_manifest(&manifest)?;
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .context("failed to create runtime")?;
+    // Loading copies the components' `files` into the given directory, which
+    // must not be the bundle that is only being inspected.
+    let staging_dir = std::env::temp_dir().join(format!("spin-validate-{}", std::process::id()));
+    std::fs::create_dir_all(&staging_dir)
+        .with_context(|| format!("failed to create staging directory {staging_dir:?}"))?;
+    let app = rt.block_on(spin_loader::from_file(&manifest, Some(&staging_dir)));
+    let _ = std::fs::remove_dir_all(&staging_dir);
+    let app = app.with_context(|| format!("failed to load spin manifest {manifest:?}"))?;
+
+    check_components(&app)
+}
+
+/// The spin manifest to load, `value` if set and `spin.toml` otherwise. Relative
+/// paths are resolved against `working_dir`.
+pub fn manifest_path(working_dir: &Path, value: Option<String>) -> PathBuf {
+    working_dir.join(value.as_deref().unwrap_or("spin.toml"))
+}
+
+/// Checks that the spin manifest at `path` exists.
+pub fn check_manifest(path: &Path) -> Result<()> {
+    if !path.is_file() {
+        bail!("spin manifest {path:?} not found");
+    }
+    Ok(())
+}
+
+/// Checks that every component of `app` is loaded from a Wasm binary.
+pub fn check_components(app: &spin_manifest::Application) -> Result<()> {
+    for component in &app.components {
+        if let spin_manifest::ModuleSource::FileReference(path) = &component.source {
+            check_module(path).with_context(|| format!("invalid component {:?}", component.id))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use containerd_shim_common::bundle::fixture_bundle;
+
+    use super::*;
+
+    const MANIFEST: &str = r#"
+spin_manifest_version = "1"
+name = "hello"
+version = "0.1.0"
+trigger = { type = "http", base = "/" }
+
+[[component]]
+id = "hello"
+source = "hello.wasm"
+[component.trigger]
+route = "/hello"
+"#;
+
+    const MODULE: &[u8] = b"\0asm\x01\0\0\0";
+
+    const CONFIG: &str = r#"{"root": {"path": "rootfs"}}"#;
+
+    #[test]
+    fn accepts_valid_bundle() {
+        let bundle = fixture_bundle(
+            "spin-validate-valid",
+            CONFIG,
+            &[("spin.toml", MANIFEST.as_bytes()), ("hello.wasm", MODULE)],
+        );
+        let res = validate_bundle(&bundle);
+        std::fs::remove_dir_all(&bundle).unwrap();
+        res.unwrap();
+    }
+
+    /// Relative paths of all files below `dir`.
+    fn list_files(dir: &Path) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                files.extend(
+                    list_files(&path)
+                        .into_iter()
+                        .map(|f| Path::new(path.file_name().unwrap()).join(f)),
+                );
+            } else {
+                files.push(PathBuf::from(path.file_name().unwrap()));
+            }
+        }
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn leaves_bundle_unchanged() {
+        let manifest = MANIFEST.replace(
+            "source = \"hello.wasm\"",
+            "source = \"hello.wasm\"\nfiles = [\"static/*\"]",
+        );
+        let bundle = fixture_bundle(
+            "spin-validate-files",
+            CONFIG,
+            &[
+                ("spin.toml", manifest.as_bytes()),
+                ("hello.wasm", MODULE),
+                ("static/index.html", b"hello"),
+            ],
+        );
+        let before = list_files(&bundle);
+        let res = validate_bundle(&bundle);
+        let after = list_files(&bundle);
+        std::fs::remove_dir_all(&bundle).unwrap();
+        res.unwrap();
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn rejects_missing_module() {
+        let bundle = fixture_bundle(
+            "spin-validate-missing",
+            CONFIG,
+            &[("spin.toml", MANIFEST.as_bytes())],
+        );
+        let res = validate_bundle(&bundle);
+        std::fs::remove_dir_all(&bundle).unwrap();
+        let err = format!("{:#}", res.unwrap_err());
+        assert!(err.contains("invalid component \"hello\""), "{err}");
+        assert!(err.contains("not found"), "{err}");
+    }
+
+    #[test]
+    fn accepts_custom_manifest_path() {
+        let bundle = fixture_bundle(
+            "spin-validate-manifest-path",
+            r#"{"root": {"path": "rootfs"}, "process": {"env": ["SPIN_MANIFEST_PATH=/app/custom.toml"]}}"#,
+            &[
+                ("app/custom.toml", MANIFEST.as_bytes()),
+                ("app/hello.wasm", MODULE),
+            ],
+        );
+        let res = validate_bundle(&bundle);
+        std::fs::remove_dir_all(&bundle).unwrap();
+        res.unwrap();
+    }
+
+    #[test]
+    fn rejects_malformed_manifest() {
+        let bundle = fixture_bundle(
+            "spin-validate-malformed",
+            CONFIG,
+            &[("spin.toml", b"[[component]\nid ="), ("hello.wasm", MODULE)],
+        );
+        let res = validate_bundle(&bundle);
+        std::fs::remove_dir_all(&bundle).unwrap();
+        assert!(format!("{:#}", res.unwrap_err()).contains("failed to load spin manifest"));
+    }
+
+    #[test]
+    fn resolves_manifest_path() {
+        let cwd = Path::new("/app");
+        assert_eq!(manifest_path(cwd, None), Path::new("/app/spin.toml"));
+        assert_eq!(
+            manifest_path(cwd, Some("conf/spin.toml".into())),
+            Path::new("/app/conf/spin.toml")
+        );
+        assert_eq!(
+            manifest_path(cwd, Some("/srv/spin.toml".into())),
+            Path::new("/srv/spin.toml")
+        );
+    }
+}